@@ -1,6 +1,10 @@
+use std::any::Any;
+use std::env;
 use std::error::{FromError, Error};
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use backtrace::Backtrace;
 use conduit::Response;
 
 use util::json_response;
@@ -8,6 +12,27 @@ use util::json_response;
 #[derive(RustcEncodable)] struct StringError { detail: String }
 #[derive(RustcEncodable)] struct Bad { errors: Vec<StringError> }
 
+static SHOW_CAUSE_CHAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn show_cause_chain(enabled: bool) {
+    SHOW_CAUSE_CHAIN.store(enabled, Ordering::SeqCst);
+}
+
+fn backtrace_enabled() -> bool {
+    match env::var("RUST_BACKTRACE") {
+        Ok(ref s) if s != "0" => true,
+        _ => false,
+    }
+}
+
+fn maybe_backtrace() -> Option<Backtrace> {
+    if backtrace_enabled() {
+        Some(Backtrace::new_unresolved())
+    } else {
+        None
+    }
+}
+
 // =============================================================================
 // CargoError trait
 
@@ -17,14 +42,52 @@ pub trait CargoError: Send + fmt::Display {
 
     fn response(&self) -> Option<Response> {
         if self.human() {
-            Some(json_response(&Bad {
-                errors: vec![StringError { detail: self.description().to_string() }]
-            }))
+            let mut errors = vec![StringError { detail: self.description().to_string() }];
+            if SHOW_CAUSE_CHAIN.load(Ordering::SeqCst) {
+                let mut cause = self.cause();
+                while let Some(c) = cause {
+                    if !c.human() { break; }
+                    errors.push(StringError { detail: c.description().to_string() });
+                    cause = c.cause();
+                }
+            }
+            Some(json_response(&Bad { errors: errors }))
         } else {
             self.cause().and_then(|cause| cause.response())
         }
     }
     fn human(&self) -> bool { false }
+
+    fn location(&self) -> Option<(&'static str, u32)> { None }
+
+    fn backtrace(&self) -> Option<&Backtrace> { None }
+
+    fn as_any(&self) -> &Any;
+
+    fn find_cause<T: CargoError + 'static>(&self) -> Option<&T> where Self: Sized {
+        find_cause(self)
+    }
+
+    fn root_cause(&self) -> &CargoError {
+        let mut err: &CargoError = self;
+        while let Some(cause) = err.cause() {
+            err = cause;
+        }
+        err
+    }
+}
+
+pub fn find_cause<'a, T: CargoError + 'static>(err: &'a CargoError) -> Option<&'a T> {
+    let mut err = err;
+    loop {
+        if let Some(t) = err.as_any().downcast_ref::<T>() {
+            return Some(t);
+        }
+        match err.cause() {
+            Some(c) => err = c,
+            None => return None,
+        }
+    }
 }
 
 impl fmt::Debug for Box<CargoError> {
@@ -38,12 +101,18 @@ impl CargoError for Box<CargoError> {
     fn cause(&self) -> Option<&CargoError> { (**self).cause() }
     fn human(&self) -> bool { (**self).human() }
     fn response(&self) -> Option<Response> { (**self).response() }
+    fn location(&self) -> Option<(&'static str, u32)> { (**self).location() }
+    fn backtrace(&self) -> Option<&Backtrace> { (**self).backtrace() }
+    fn as_any(&self) -> &Any { (**self).as_any() }
 }
 impl<T: CargoError> CargoError for Box<T> {
     fn description(&self) -> &str { (**self).description() }
     fn cause(&self) -> Option<&CargoError> { (**self).cause() }
     fn human(&self) -> bool { (**self).human() }
     fn response(&self) -> Option<Response> { (**self).response() }
+    fn location(&self) -> Option<(&'static str, u32)> { (**self).location() }
+    fn backtrace(&self) -> Option<&Backtrace> { (**self).backtrace() }
+    fn as_any(&self) -> &Any { (**self).as_any() }
 }
 
 pub type CargoResult<T> = Result<T, Box<CargoError>>;
@@ -52,40 +121,57 @@ pub type CargoResult<T> = Result<T, Box<CargoError>>;
 // Chaining errors
 
 pub trait ChainError<T> {
-    fn chain_error<E, F>(self, callback: F) -> CargoResult<T>
+    fn chain_error<E, F>(self, location: (&'static str, u32), callback: F) -> CargoResult<T>
                          where E: CargoError, F: FnOnce() -> E;
 }
 
+#[macro_export]
+macro_rules! chain_error {
+    ($e:expr, $c:expr) => (
+        ChainError::chain_error($e, (file!(), line!()), $c)
+    )
+}
+
 struct ChainedError<E> {
     error: E,
     cause: Box<CargoError>,
+    location: (&'static str, u32),
+}
+
+struct LocatedError<E> {
+    error: E,
+    location: (&'static str, u32),
 }
 
 impl<T, F> ChainError<T> for F where F: FnOnce() -> CargoResult<T> {
-    fn chain_error<E, C>(self, callback: C) -> CargoResult<T>
+    fn chain_error<E, C>(self, location: (&'static str, u32), callback: C) -> CargoResult<T>
                          where E: CargoError, C: FnOnce() -> E {
-        self().chain_error(callback)
+        self().chain_error(location, callback)
     }
 }
 
 impl<T, E: CargoError> ChainError<T> for Result<T, E> {
-    fn chain_error<E2, C>(self, callback: C) -> CargoResult<T>
+    fn chain_error<E2, C>(self, location: (&'static str, u32), callback: C) -> CargoResult<T>
                          where E2: CargoError, C: FnOnce() -> E2 {
         self.map_err(move |err| {
             Box::new(ChainedError {
                 error: callback(),
                 cause: Box::new(err),
+                location: location,
             }) as Box<CargoError>
         })
     }
 }
 
 impl<T> ChainError<T> for Option<T> {
-    fn chain_error<E, C>(self, callback: C) -> CargoResult<T>
+    fn chain_error<E, C>(self, location: (&'static str, u32), callback: C) -> CargoResult<T>
                          where E: CargoError, C: FnOnce() -> E {
         match self {
             Some(t) => Ok(t),
-            None => Err(Box::new(callback()) as Box<CargoError>),
+            None => Err(Box::new(LocatedError {
+                error: callback(),
+                location: location,
+            }) as Box<CargoError>),
         }
     }
 }
@@ -93,8 +179,27 @@ impl<T> ChainError<T> for Option<T> {
 impl<E: CargoError> CargoError for ChainedError<E> {
     fn description(&self) -> &str { self.error.description() }
     fn cause(&self) -> Option<&CargoError> { Some(&*self.cause) }
-    fn response(&self) -> Option<Response> { self.error.response() }
+    // Only the plain ConcreteCargoError case renders the cause chain; anything
+    // else (NotFound, ErrorKind, ...) keeps its own status via self.error.response().
+    fn response(&self) -> Option<Response> {
+        if self.human() && self.error.as_any().downcast_ref::<ConcreteCargoError>().is_some() {
+            let mut errors = vec![StringError { detail: self.description().to_string() }];
+            if SHOW_CAUSE_CHAIN.load(Ordering::SeqCst) {
+                let mut cause = self.cause();
+                while let Some(c) = cause {
+                    if !c.human() { break; }
+                    errors.push(StringError { detail: c.description().to_string() });
+                    cause = c.cause();
+                }
+            }
+            return Some(json_response(&Bad { errors: errors }));
+        }
+        self.error.response()
+    }
     fn human(&self) -> bool { self.error.human() }
+    fn location(&self) -> Option<(&'static str, u32)> { Some(self.location) }
+    fn backtrace(&self) -> Option<&Backtrace> { self.error.backtrace() }
+    fn as_any(&self) -> &Any { self.error.as_any() }
 }
 
 impl<E: CargoError> fmt::Display for ChainedError<E> {
@@ -103,16 +208,50 @@ impl<E: CargoError> fmt::Display for ChainedError<E> {
     }
 }
 
+impl<E: CargoError> CargoError for LocatedError<E> {
+    fn description(&self) -> &str { self.error.description() }
+    fn cause(&self) -> Option<&CargoError> { self.error.cause() }
+    fn response(&self) -> Option<Response> { self.error.response() }
+    fn human(&self) -> bool { self.error.human() }
+    fn location(&self) -> Option<(&'static str, u32)> { Some(self.location) }
+    fn backtrace(&self) -> Option<&Backtrace> { self.error.backtrace() }
+    fn as_any(&self) -> &Any { self.error.as_any() }
+}
+
+impl<E: CargoError> fmt::Display for LocatedError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
 // =============================================================================
 // Error impls
 
-impl<E: Error + Send> CargoError for E {
+impl<E: Error + Send + 'static> CargoError for E {
     fn description(&self) -> &str { Error::description(self) }
+    fn as_any(&self) -> &Any { self }
 }
 
-impl<E: Error + Send> FromError<E> for Box<CargoError> {
+struct WithBacktrace<E> {
+    error: E,
+    backtrace: Option<Backtrace>,
+}
+
+impl<E: Error + Send> fmt::Display for WithBacktrace<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl<E: Error + Send + 'static> CargoError for WithBacktrace<E> {
+    fn description(&self) -> &str { Error::description(&self.error) }
+    fn backtrace(&self) -> Option<&Backtrace> { self.backtrace.as_ref() }
+    fn as_any(&self) -> &Any { &self.error }
+}
+
+impl<E: Error + Send + 'static> FromError<E> for Box<CargoError> {
     fn from_error(err: E) -> Box<CargoError> {
-        Box::new(err) as Box<CargoError>
+        Box::new(WithBacktrace { error: err, backtrace: maybe_backtrace() }) as Box<CargoError>
     }
 }
 
@@ -124,6 +263,7 @@ struct ConcreteCargoError {
     detail: Option<String>,
     cause: Option<Box<CargoError>>,
     human: bool,
+    backtrace: Option<Backtrace>,
 }
 
 impl fmt::Display for ConcreteCargoError {
@@ -141,6 +281,8 @@ impl CargoError for ConcreteCargoError {
     fn description(&self) -> &str { self.description.as_slice() }
     fn cause(&self) -> Option<&CargoError> { self.cause.as_ref().map(|c| &**c) }
     fn human(&self) -> bool { self.human }
+    fn backtrace(&self) -> Option<&Backtrace> { self.backtrace.as_ref() }
+    fn as_any(&self) -> &Any { self }
 }
 
 pub struct NotFound;
@@ -155,6 +297,8 @@ impl CargoError for NotFound {
         response.status = (404, "Not Found");
         return Some(response);
     }
+
+    fn as_any(&self) -> &Any { self }
 }
 
 impl fmt::Display for NotFound {
@@ -177,6 +321,8 @@ impl CargoError for Unauthorized {
         response.status = (403, "Forbidden");
         return Some(response);
     }
+
+    fn as_any(&self) -> &Any { self }
 }
 
 impl fmt::Display for Unauthorized {
@@ -185,6 +331,76 @@ impl fmt::Display for Unauthorized {
     }
 }
 
+// =============================================================================
+// ErrorKind: structured "human" errors with a known HTTP status
+
+pub enum ErrorKind {
+    Conflict,
+    BadRequest(String),
+    TooManyRequests,
+}
+
+impl ErrorKind {
+    fn status(&self) -> (u16, &'static str) {
+        match *self {
+            ErrorKind::Conflict => (409, "Conflict"),
+            ErrorKind::BadRequest(..) => (400, "Bad Request"),
+            ErrorKind::TooManyRequests => (429, "Too Many Requests"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Conflict => "Conflict".fmt(f),
+            ErrorKind::BadRequest(ref s) => s.fmt(f),
+            ErrorKind::TooManyRequests => "Too Many Requests".fmt(f),
+        }
+    }
+}
+
+impl CargoError for ErrorKind {
+    fn description(&self) -> &str {
+        match *self {
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::BadRequest(ref s) => s.as_slice(),
+            ErrorKind::TooManyRequests => "too many requests",
+        }
+    }
+
+    fn response(&self) -> Option<Response> {
+        let (code, reason) = self.status();
+        let mut response = json_response(&Bad {
+            errors: vec![StringError { detail: self.to_string() }],
+        });
+        response.status = (code, reason);
+        Some(response)
+    }
+
+    fn human(&self) -> bool { true }
+
+    fn as_any(&self) -> &Any { self }
+}
+
+impl FromError<ErrorKind> for Box<CargoError> {
+    fn from_error(err: ErrorKind) -> Box<CargoError> {
+        Box::new(err) as Box<CargoError>
+    }
+}
+
+pub fn conflict() -> Box<CargoError> {
+    Box::new(ErrorKind::Conflict) as Box<CargoError>
+}
+
+pub fn bad_request<S: fmt::Display>(error: S) -> Box<CargoError> {
+    Box::new(ErrorKind::BadRequest(error.to_string())) as Box<CargoError>
+}
+
+pub fn too_many_requests() -> Box<CargoError> {
+    Box::new(ErrorKind::TooManyRequests) as Box<CargoError>
+}
+
 pub fn internal_error<S1: Str, S2: Str>(error: S1,
                                         detail: S2) -> Box<CargoError> {
     Box::new(ConcreteCargoError {
@@ -192,6 +408,7 @@ pub fn internal_error<S1: Str, S2: Str>(error: S1,
         detail: Some(detail.as_slice().to_string()),
         cause: None,
         human: false,
+        backtrace: maybe_backtrace(),
     }) as Box<CargoError>
 }
 
@@ -201,6 +418,7 @@ pub fn internal<S: fmt::Display>(error: S) -> Box<CargoError> {
         detail: None,
         cause: None,
         human: false,
+        backtrace: maybe_backtrace(),
     }) as Box<CargoError>
 }
 
@@ -210,6 +428,7 @@ pub fn human<S: fmt::Display>(error: S) -> Box<CargoError> {
         detail: None,
         cause: None,
         human: true,
+        backtrace: None,
     }) as Box<CargoError>
 }
 
@@ -220,12 +439,30 @@ pub fn std_error(e: Box<CargoError>) -> Box<Error+Send> {
     }
     impl fmt::Display for E {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if let Some(loc) = self.0.location() {
+                try!(write!(f, "{}:{}: ", loc.0, loc.1));
+            }
             try!(write!(f, "{}", self.0));
 
             let mut err = &*self.0;
+            let mut backtraces = Vec::new();
+            if let Some(bt) = err.backtrace() { backtraces.push(bt); }
             while let Some(cause) = err.cause() {
                 err = cause;
-                try!(write!(f, "\nCaused by: {}", err));
+                try!(write!(f, "\nCaused by: "));
+                if let Some(loc) = err.location() {
+                    try!(write!(f, "{}:{}: ", loc.0, loc.1));
+                }
+                try!(write!(f, "{}", err));
+                if let Some(bt) = err.backtrace() { backtraces.push(bt); }
+            }
+
+            // Symbols aren't resolved until formatted here, so the capture
+            // itself is cheap even with backtraces enabled.
+            for bt in backtraces {
+                let mut bt = bt.clone();
+                bt.resolve();
+                try!(write!(f, "\n{:?}", bt));
             }
 
             Ok(())
@@ -233,3 +470,89 @@ pub fn std_error(e: Box<CargoError>) -> Box<Error+Send> {
     }
     Box::new(E(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ResetShowCauseChain;
+    impl Drop for ResetShowCauseChain {
+        fn drop(&mut self) { show_cause_chain(false); }
+    }
+
+    #[test]
+    fn chain_error_response_surfaces_the_real_cause_chain() {
+        let _reset = ResetShowCauseChain;
+        show_cause_chain(true);
+
+        let io_err: CargoResult<()> = Err(human("Error reading 'foo.txt'"));
+        let chained = io_err.chain_error(("test.rs", 1), || human("func1 error"));
+        let response = chained.unwrap_err().response().expect("human errors always respond");
+        let body = response.body.read_to_string().unwrap();
+
+        assert!(body.contains("func1 error"));
+        assert!(body.contains("Error reading 'foo.txt'"));
+    }
+
+    #[test]
+    fn chain_error_preserves_the_wrapped_errors_status() {
+        let db_err: CargoResult<()> = Err(internal("db failure"));
+        let chained = db_err.chain_error(("test.rs", 1), || NotFound);
+        assert_eq!(chained.unwrap_err().response().unwrap().status.0, 404);
+
+        let db_err: CargoResult<()> = Err(internal("db failure"));
+        let chained = db_err.chain_error(("test.rs", 1), || ErrorKind::Conflict);
+        assert_eq!(chained.unwrap_err().response().unwrap().status.0, 409);
+    }
+
+    #[test]
+    fn std_error_prefixes_caused_by_lines_with_file_and_line() {
+        let io_err: CargoResult<()> = Err(human("Error reading 'foo.txt'"));
+        let inner = chain_error!(io_err, || human("func1 error"));
+        let chained = chain_error!(inner, || human("func2 error"));
+        let rendered = format!("{}", std_error(chained.unwrap_err()));
+
+        assert!(rendered.contains(&format!("{}:", file!())));
+        assert!(rendered.contains("Caused by: "));
+        assert!(rendered.contains(&format!("\nCaused by: {}:", file!())));
+        assert!(rendered.contains("Error reading 'foo.txt'"));
+    }
+
+    #[test]
+    fn find_cause_and_root_cause_walk_the_chain() {
+        let cause: CargoResult<()> = Err(Box::new(NotFound) as Box<CargoError>);
+        let chained = cause.chain_error(("test.rs", 1), || human("could not list crates"));
+        let err = chained.unwrap_err();
+
+        assert!(err.find_cause::<NotFound>().is_some());
+        assert!(err.find_cause::<Unauthorized>().is_none());
+        assert_eq!(err.root_cause().description(), "not found");
+    }
+
+    #[test]
+    fn error_kind_response_sets_the_matching_status_code() {
+        assert_eq!(ErrorKind::Conflict.response().unwrap().status.0, 409);
+        assert_eq!(ErrorKind::BadRequest("bad".to_string()).response().unwrap().status.0, 400);
+        assert_eq!(ErrorKind::TooManyRequests.response().unwrap().status.0, 429);
+    }
+
+    #[test]
+    fn internal_errors_only_capture_a_backtrace_when_rust_backtrace_is_set() {
+        env::remove_var("RUST_BACKTRACE");
+        assert!(internal("boom").backtrace().is_none());
+
+        env::set_var("RUST_BACKTRACE", "1");
+        assert!(internal("boom").backtrace().is_some());
+        env::remove_var("RUST_BACKTRACE");
+    }
+
+    #[test]
+    fn std_error_appends_a_resolved_backtrace_when_present() {
+        env::set_var("RUST_BACKTRACE", "1");
+        let rendered = format!("{}", std_error(internal("boom")));
+        env::remove_var("RUST_BACKTRACE");
+
+        assert!(rendered.contains("boom"));
+        assert!(rendered.lines().count() > 1);
+    }
+}